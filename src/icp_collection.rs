@@ -1,73 +1,261 @@
-use std::ops::{Deref, DerefMut};
-
+use crate::ops::RealOps;
 use crate::ICPPoint;
 use kdtree::{distance::squared_euclidean, KdTree};
 use nalgebra as na;
 
-pub(crate) trait ICPCol<T: ICPPoint> {
+/// Scalars usable as an ICP coordinate type: a real field that also converts
+/// to and from `f64` so the `f64`-keyed [`KdTree`] and the literal thresholds
+/// stay expressible regardless of the working precision, and whose float ops
+/// route through [`crate::ops`] for deterministic, `no_std`-friendly math.
+pub trait IcpScalar:
+    na::RealField + Copy + simba::scalar::SupersetOf<f64> + simba::scalar::SubsetOf<f64> + RealOps
+{
+}
+impl<S: na::RealField + Copy + simba::scalar::SupersetOf<f64> + simba::scalar::SubsetOf<f64> + RealOps> IcpScalar
+    for S
+{
+}
+
+/// Coordinate in the precision used by the spatial index.
+fn key<S: IcpScalar>(p: na::Point2<S>) -> [f64; 2] {
+    [na::convert(p.x), na::convert(p.y)]
+}
+
+/// Build a scalar from an `f64` literal (thresholds, reciprocals of counts).
+fn scalar<S: IcpScalar>(x: f64) -> S {
+    na::convert(x)
+}
+
+/// Axis-aligned bounding box of a scan, in the working precision.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb<S: IcpScalar> {
+    pub min: na::Point2<S>,
+    pub max: na::Point2<S>,
+}
+
+/// A rectangular region of interest given by its lower corner `position` and
+/// its `size` extent. Used to crop a scan to a sub-window before matching.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect<S: IcpScalar> {
+    pub position: na::Point2<S>,
+    pub size: na::Vector2<S>,
+}
+
+impl<S: IcpScalar> Rect<S> {
+    /// Whether `p` falls inside the rectangle (inclusive of its borders).
+    pub fn contains(&self, p: na::Point2<S>) -> bool {
+        let max = self.position + self.size;
+        p.x >= self.position.x && p.y >= self.position.y && p.x <= max.x && p.y <= max.y
+    }
+}
+
+pub(crate) trait ICPCol<S: IcpScalar, T: ICPPoint<S>> {
     type Collection;
-    fn new(col: Self::Collection)->Self;
+    fn new(col: Self::Collection) -> Self;
     fn get_points(&self) -> &[T];
     fn inner(self) -> Self::Collection;
+
+    /// Axis-aligned bounding box of the contained points, or `None` if empty.
+    fn aabb(&self) -> Option<Aabb<S>> {
+        let pts = self.get_points();
+        let first = pts.first()?.point();
+        let (mut min, mut max) = (first, first);
+        for p in pts.iter().map(|p| p.point()) {
+            min.x = min.x.min(p.x);
+            min.y = min.y.min(p.y);
+            max.x = max.x.max(p.x);
+            max.y = max.y.max(p.y);
+        }
+        Some(Aabb { min, max })
+    }
 }
 
-pub(crate) trait ICPColCenterOfMass<T: ICPPoint>: ICPCol<T> {
-    fn calculate_center_of_mass(&self) -> na::Point2<f32> {
-        let mut center_of_mass = na::Point2::new(0.0, 0.0);
+pub(crate) trait ICPColCenterOfMass<S: IcpScalar, T: ICPPoint<S>>: ICPCol<S, T> {
+    fn calculate_center_of_mass(&self) -> na::Point2<S> {
+        let mut center_of_mass = na::Point2::new(S::zero(), S::zero());
         let pts = self.get_points();
         pts.iter().for_each(|p| center_of_mass += p.point().coords);
-        center_of_mass /= pts.len() as f32;
-        return center_of_mass;
+        center_of_mass /= scalar(pts.len() as f64);
+        center_of_mass
     }
 
+    #[allow(dead_code)]
     fn assert_valid(&self) {
         self.get_points().iter().for_each(|p| assert!(p.is_data_valid()));
         assert!(points_equal_tolerance(self.get_center_of_mass(), self.calculate_center_of_mass()));
     }
 
-    fn get_center_of_mass(&self) -> na::Point2<f32>;
+    fn get_center_of_mass(&self) -> na::Point2<S>;
 }
 
-pub(crate) struct KDTreedIcpCollection<'a,T: ICPPoint> {
+/// Number of nearest neighbours used to estimate a per-point surface normal.
+const NORMAL_NEIGHBOURS: usize = 6;
+
+pub(crate) struct KDTreedIcpCollection<'a, S: IcpScalar, T: ICPPoint<S>> {
     collection: &'a [T],
-    kd_tree: KdTree<f32, usize, [f32; 2]>,
-    center_of_mass: na::Point2<f32>,
+    kd_tree: KdTree<f64, usize, [f64; 2]>,
+    center_of_mass: na::Point2<S>,
+    /// Per-point surface normals, estimated lazily on the first point-to-plane
+    /// query: the default point-to-point path never reads them, so the O(n·k)
+    /// k-NN + eigen pass is skipped entirely when it isn't needed.
+    normals: Option<Vec<na::Vector2<S>>>,
+    /// Bounding box of the points actually inserted into the tree, so a scan
+    /// cropped via [`new_cropped`](Self::new_cropped) reports the box of its
+    /// matched subset rather than the original slice. `None` when empty.
+    bounds: Option<Aabb<S>>,
 }
 
-impl<'a,T: ICPPoint> KDTreedIcpCollection<'a,T> {
-    pub fn closest_point_kd(&mut self, point: na::Point2<f32>) -> na::Point2<f32> {
-        let pt = self
+/// Grow an optional bounding box to include `p`.
+fn extend_bounds<S: IcpScalar>(bounds: &mut Option<Aabb<S>>, p: na::Point2<S>) {
+    match bounds {
+        None => *bounds = Some(Aabb { min: p, max: p }),
+        Some(b) => {
+            b.min.x = b.min.x.min(p.x);
+            b.min.y = b.min.y.min(p.y);
+            b.max.x = b.max.x.max(p.x);
+            b.max.y = b.max.y.max(p.y);
+        }
+    }
+}
+
+impl<'a, S: IcpScalar, T: ICPPoint<S>> KDTreedIcpCollection<'a, S, T> {
+    /// Build the spatial index over only those points of `points` that fall
+    /// inside `roi`. Points outside the region are never inserted into the
+    /// tree, so they can neither be matched nor drag the centroid; this prunes
+    /// the reference scan to a region of interest without reallocating it.
+    pub fn new_cropped(points: &'a [T], roi: &Rect<S>) -> Self {
+        let mut kd_tree = KdTree::new(2);
+        let mut center_of_mass = na::Point2::new(S::zero(), S::zero());
+        let mut bounds = None;
+        let mut kept = 0usize;
+        points.iter().enumerate().for_each(|(i, p)| {
+            let pt = p.point();
+            if roi.contains(pt) {
+                kd_tree.add(key(pt), i).unwrap();
+                center_of_mass += pt.coords;
+                extend_bounds(&mut bounds, pt);
+                kept += 1;
+            }
+        });
+        if kept > 0 {
+            center_of_mass /= scalar(kept as f64);
+        }
+
+        Self {
+            kd_tree,
+            collection: points,
+            center_of_mass,
+            normals: None,
+            bounds,
+        }
+    }
+
+    /// Nearest reference point together with the squared distance to it, or
+    /// `None` when the index is empty (e.g. a region of interest that excludes
+    /// every reference point).
+    ///
+    /// The squared distance is returned directly from the `iter_nearest`
+    /// query already performed here, so correspondence-rejection stages
+    /// (max-distance gating, trimmed ICP) need no extra tree lookups.
+    pub fn closest_point_kd(&mut self, point: na::Point2<S>) -> Option<(na::Point2<S>, S)> {
+        let (dist_sq, idx) = self
             .kd_tree
-            .iter_nearest(&[point.x, point.y], &squared_euclidean)
+            .iter_nearest(&key(point), &squared_euclidean)
             .unwrap()
-            .next()
-            .unwrap();
+            .next()?;
 
-        self.collection[*pt.1].point()
+        Some((self.collection[*idx].point(), scalar(dist_sq)))
     }
 
+    /// Nearest reference point together with its surface normal, or `None` when
+    /// the index is empty.
+    ///
+    /// Used by the point-to-plane solver; the normal is the one supplied by
+    /// [`ICPPoint::normal`] or, failing that, the value estimated from the
+    /// point's neighbourhood the first time this method is called.
+    pub fn closest_point_with_normal_kd(&mut self, point: na::Point2<S>) -> Option<(na::Point2<S>, na::Vector2<S>)> {
+        let idx = *self
+            .kd_tree
+            .iter_nearest(&key(point), &squared_euclidean)
+            .unwrap()
+            .next()?
+            .1;
 
+        if self.normals.is_none() {
+            self.normals = Some(estimate_normals(&self.kd_tree, self.collection));
+        }
+        let normals = self.normals.as_ref().unwrap();
+        Some((self.collection[idx].point(), normals[idx]))
+    }
 }
 
-impl<'a,T: ICPPoint> ICPColCenterOfMass<T> for KDTreedIcpCollection<'a,T> {
-    fn get_center_of_mass(&self) -> na::Point2<f32> {
+/// Estimate a unit surface normal for every point from its k nearest
+/// neighbours: the normal is the eigenvector of the local 2x2 covariance
+/// matrix belonging to the smallest eigenvalue.
+fn estimate_normals<S: IcpScalar, T: ICPPoint<S>>(
+    kd_tree: &KdTree<f64, usize, [f64; 2]>,
+    collection: &[T],
+) -> Vec<na::Vector2<S>> {
+    collection
+        .iter()
+        .map(|p| {
+            if let Some(n) = p.normal() {
+                return n.try_normalize(S::zero()).unwrap_or(n);
+            }
+
+            let p = p.point();
+            let neighbours = kd_tree
+                .iter_nearest(&key(p), &squared_euclidean)
+                .unwrap()
+                .take(NORMAL_NEIGHBOURS)
+                .map(|(_, &i)| collection[i].point())
+                .collect::<Vec<_>>();
+
+            if neighbours.len() < 2 {
+                return na::Vector2::zeros();
+            }
+
+            let mut centroid = na::Point2::new(S::zero(), S::zero());
+            neighbours.iter().for_each(|q| centroid += q.coords);
+            centroid /= scalar(neighbours.len() as f64);
+
+            let mut cov = na::Matrix2::zeros();
+            for q in &neighbours {
+                let d = q - centroid;
+                cov += d * d.transpose();
+            }
+
+            let eigen = na::SymmetricEigen::new(cov);
+            let min = if eigen.eigenvalues[0] <= eigen.eigenvalues[1] { 0 } else { 1 };
+            let normal = eigen.eigenvectors.column(min).into_owned();
+            normal.try_normalize(S::zero()).unwrap_or_else(na::Vector2::zeros)
+        })
+        .collect()
+}
+
+impl<'a, S: IcpScalar, T: ICPPoint<S>> ICPColCenterOfMass<S, T> for KDTreedIcpCollection<'a, S, T> {
+    fn get_center_of_mass(&self) -> na::Point2<S> {
         self.center_of_mass
     }
 }
 
-impl<'a,T: ICPPoint> ICPCol<T> for KDTreedIcpCollection<'a,T> {
-    type Collection = &'a[T];
+impl<'a, S: IcpScalar, T: ICPPoint<S>> ICPCol<S, T> for KDTreedIcpCollection<'a, S, T> {
+    type Collection = &'a [T];
     fn new(points: &'a [T]) -> Self {
         let mut kd_tree = KdTree::new(2);
+        let mut bounds = None;
         points.iter().enumerate().for_each(|(i, p)| {
-            let p = p.point();
-            kd_tree.add([p.x, p.y], i).unwrap()
+            let pt = p.point();
+            kd_tree.add(key(pt), i).unwrap();
+            extend_bounds(&mut bounds, pt);
         });
 
         let mut this = Self {
             kd_tree,
             collection: points,
-            center_of_mass: Default::default(),
+            center_of_mass: na::Point2::new(S::zero(), S::zero()),
+            normals: None,
+            bounds,
         };
         this.center_of_mass = this.calculate_center_of_mass();
         this
@@ -78,20 +266,26 @@ impl<'a,T: ICPPoint> ICPCol<T> for KDTreedIcpCollection<'a,T> {
     fn inner(self) -> Self::Collection {
         self.collection
     }
+
+    /// Bounding box of the points held in the tree, which for a cropped
+    /// collection is the ROI subset rather than the full backing slice.
+    fn aabb(&self) -> Option<Aabb<S>> {
+        self.bounds
+    }
 }
 
 #[derive(Clone)]
-pub(crate) struct ICPCollection<T: ICPPoint> {
+pub(crate) struct ICPCollection<S: IcpScalar, T: ICPPoint<S>> {
     points: Vec<T>,
-    center_of_mass: na::Point2<f32>,
+    center_of_mass: na::Point2<S>,
 }
 
-impl<T: ICPPoint> ICPCol<T> for ICPCollection<T> {
+impl<S: IcpScalar, T: ICPPoint<S>> ICPCol<S, T> for ICPCollection<S, T> {
     type Collection = Vec<T>;
     fn new(points: Vec<T>) -> Self {
         let mut this = Self {
             points,
-            center_of_mass: Default::default(),
+            center_of_mass: na::Point2::new(S::zero(), S::zero()),
         };
         this.center_of_mass = this.calculate_center_of_mass();
         this
@@ -104,17 +298,14 @@ impl<T: ICPPoint> ICPCol<T> for ICPCollection<T> {
     }
 }
 
-impl<T: ICPPoint> ICPColCenterOfMass<T> for ICPCollection<T> {
-    fn get_center_of_mass(&self) -> na::Point2<f32> {
+impl<S: IcpScalar, T: ICPPoint<S>> ICPColCenterOfMass<S, T> for ICPCollection<S, T> {
+    fn get_center_of_mass(&self) -> na::Point2<S> {
         self.center_of_mass
     }
 }
 
-impl<T: ICPPoint> ICPCollection<T> {
-
-
-
-    pub fn translate(&mut self, x: f32, y: f32) -> &mut Self {
+impl<S: IcpScalar, T: ICPPoint<S>> ICPCollection<S, T> {
+    pub fn translate(&mut self, x: S, y: S) -> &mut Self {
         self.points.iter_mut().for_each(|p| {
             //v.x += val;
             *p = p.translate(x, y)
@@ -127,7 +318,7 @@ impl<T: ICPPoint> ICPCollection<T> {
         self
     }
 
-    pub fn rotate(&mut self, rot_rad: f32) -> &mut Self {
+    pub fn rotate(&mut self, rot_rad: S) -> &mut Self {
         self.points.iter_mut().for_each(|p| {
             *p = p.rotate(rot_rad);
         });
@@ -141,12 +332,33 @@ impl<T: ICPPoint> ICPCollection<T> {
         ));
         self
     }
+}
+
+/// Voxel-grid subsample: hash points into square cells of side `cell` and keep
+/// a single representative per cell. Used to build the coarse levels of the
+/// multiresolution ICP pyramid; a non-positive `cell` returns the input
+/// untouched.
+pub(crate) fn voxel_filter<S: IcpScalar, T: ICPPoint<S>>(points: &[T], cell: S) -> Vec<T> {
+    if cell <= S::zero() {
+        return points.to_vec();
+    }
 
+    let cell: f64 = na::convert(cell);
+    let mut seen = std::collections::HashSet::new();
+    let mut out = Vec::new();
+    for p in points {
+        let [x, y] = key(p.point());
+        let cell_key = ((x / cell).floor() as i64, (y / cell).floor() as i64);
+        if seen.insert(cell_key) {
+            out.push(p.clone());
+        }
+    }
+    out
 }
 
-fn points_equal_tolerance(p1: na::Point2<f32>, p2: na::Point2<f32>) -> bool {
-    fn floats_are_equal(a: f32, b: f32) -> bool {
-        (a - b).abs() <= 0.001
+fn points_equal_tolerance<S: IcpScalar>(p1: na::Point2<S>, p2: na::Point2<S>) -> bool {
+    fn floats_are_equal<S: IcpScalar>(a: S, b: S) -> bool {
+        (a - b).abs() <= scalar(0.001)
     }
-    return floats_are_equal(p1.x, p2.x) && floats_are_equal(p1.y, p2.y);
+    floats_are_equal(p1.x, p2.x) && floats_are_equal(p1.y, p2.y)
 }
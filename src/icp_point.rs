@@ -1,22 +1,40 @@
+use crate::ops::{self, RealOps};
 use nalgebra as na;
-pub trait ICPPoint: Clone{
-    fn translate(&self,x: f32,y: f32) -> Self;
-    fn rotate(&self,angle_rad: f32) -> Self;
-    fn point(&self) -> na::Point2<f32>;
-    fn is_data_valid(&self) -> bool;
+
+/// Rotate a point about the origin using [`crate::ops`] trig so the result is
+/// deterministic across targets under the `libm` feature.
+fn rotate_point<S: na::RealField + Copy + RealOps>(p: na::Point2<S>, angle_rad: S) -> na::Point2<S> {
+    let (s, c) = (ops::sin(angle_rad), ops::cos(angle_rad));
+    na::Point2::new(c * p.x - s * p.y, s * p.x + c * p.y)
 }
 
+/// A point that can participate in ICP, generic over the scalar type `S`
+/// (typically `f32` for embedded callers or `f64` for large-extent maps).
+pub trait ICPPoint<S: na::RealField + Copy>: Clone {
+    fn translate(&self, x: S, y: S) -> Self;
+    fn rotate(&self, angle_rad: S) -> Self;
+    fn point(&self) -> na::Point2<S>;
+    fn is_data_valid(&self) -> bool;
+    /// Surface normal at this point, if known.
+    ///
+    /// Only the reference scan needs these; they are estimated from the
+    /// k nearest neighbours in [`KDTreedIcpCollection`](crate) when not
+    /// provided, and consumed by the point-to-plane variant.
+    fn normal(&self) -> Option<na::Vector2<S>> {
+        None
+    }
+}
 
-impl ICPPoint for na::Point2<f32>{
-    fn translate(&self,x: f32,y: f32) -> Self {
-        na::Point2::new(self.x+x,self.y+y)
+impl<S: na::RealField + Copy + RealOps> ICPPoint<S> for na::Point2<S> {
+    fn translate(&self, x: S, y: S) -> Self {
+        na::Point2::new(self.x + x, self.y + y)
     }
 
-    fn rotate(&self,angle_rad: f32) -> Self {
-        na::Rotation2::new(angle_rad) * (*self)   
+    fn rotate(&self, angle_rad: S) -> Self {
+        rotate_point(*self, angle_rad)
     }
 
-    fn point(&self) -> na::Point2<f32> {
+    fn point(&self) -> na::Point2<S> {
         *self
     }
 
@@ -25,23 +43,22 @@ impl ICPPoint for na::Point2<f32>{
     }
 }
 
-
-impl ICPPoint for (f32,f32){
-    fn translate(&self,x: f32,y: f32) -> Self {
-        let new = na::Point2::new(self.0+x,self.1+y);
-        (new.x,new.y)
+impl<S: na::RealField + Copy + RealOps> ICPPoint<S> for (S, S) {
+    fn translate(&self, x: S, y: S) -> Self {
+        let new = na::Point2::new(self.0 + x, self.1 + y);
+        (new.x, new.y)
     }
 
-    fn rotate(&self,angle_rad: f32) -> Self {
-        let new = na::Rotation2::new(angle_rad) * self.point() ;
-        (new.x,new.y)
+    fn rotate(&self, angle_rad: S) -> Self {
+        let new = rotate_point(self.point(), angle_rad);
+        (new.x, new.y)
     }
 
-    fn point(&self) -> na::Point2<f32> {
-        na::Point2::new(self.0,self.1)
+    fn point(&self) -> na::Point2<S> {
+        na::Point2::new(self.0, self.1)
     }
 
     fn is_data_valid(&self) -> bool {
         true
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,137 @@
+//! Deterministic float operations for the numeric core.
+//!
+//! The rotation trig, `atan2` rotation-angle extraction and the `sqrt` hidden
+//! inside every norm/distance comparison are routed through this module so the
+//! backing implementation can be swapped out in one place. With the default
+//! `std` feature they forward to the standard-library float methods; with the
+//! `libm` feature they forward to `libm`'s `sinf`/`cosf`/`atan2f`/`sqrtf` (and
+//! their `f64` counterparts), whose results are specified bit-for-bit across
+//! platforms. That makes alignment reproducible target-to-target and lets the
+//! numeric core build under `no_std`.
+
+/// Scalar float operations needed by the solver, implemented for the concrete
+/// precisions the crate ships (`f32`/`f64`).
+pub trait RealOps: Copy {
+    fn rsin(self) -> Self;
+    fn rcos(self) -> Self;
+    // Used only by the `std`-gated solver; kept in the trait so the `no_std`
+    // numeric core stays a single coherent backend surface.
+    #[allow(dead_code)]
+    fn ratan2(self, x: Self) -> Self;
+    #[allow(dead_code)]
+    fn rsqrt(self) -> Self;
+}
+
+impl RealOps for f32 {
+    fn rsin(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::sinf(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.sin()
+        }
+    }
+
+    fn rcos(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::cosf(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.cos()
+        }
+    }
+
+    fn ratan2(self, x: Self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::atan2f(self, x)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.atan2(x)
+        }
+    }
+
+    fn rsqrt(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::sqrtf(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.sqrt()
+        }
+    }
+}
+
+impl RealOps for f64 {
+    fn rsin(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::sin(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.sin()
+        }
+    }
+
+    fn rcos(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::cos(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.cos()
+        }
+    }
+
+    fn ratan2(self, x: Self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::atan2(self, x)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.atan2(x)
+        }
+    }
+
+    fn rsqrt(self) -> Self {
+        #[cfg(feature = "libm")]
+        {
+            libm::sqrt(self)
+        }
+        #[cfg(not(feature = "libm"))]
+        {
+            self.sqrt()
+        }
+    }
+}
+
+/// `sin(x)` routed through the active backend.
+pub(crate) fn sin<S: RealOps>(x: S) -> S {
+    x.rsin()
+}
+
+/// `cos(x)` routed through the active backend.
+pub(crate) fn cos<S: RealOps>(x: S) -> S {
+    x.rcos()
+}
+
+/// `atan2(y, x)` routed through the active backend.
+#[allow(dead_code)]
+pub(crate) fn atan2<S: RealOps>(y: S, x: S) -> S {
+    y.ratan2(x)
+}
+
+/// `sqrt(x)` routed through the active backend.
+#[allow(dead_code)]
+pub(crate) fn sqrt<S: RealOps>(x: S) -> S {
+    x.rsqrt()
+}
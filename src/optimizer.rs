@@ -0,0 +1,111 @@
+use crate::icp_collection::{ICPCol, ICPCollection, IcpScalar, KDTreedIcpCollection};
+use crate::ICPPoint;
+use nalgebra as na;
+
+/// Robust loss kernel used to down-weight outlier correspondences when solving
+/// the weighted normal equations `(JᵀWJ)Δ = −JᵀW r`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RobustKernel {
+    /// Huber loss: `w = 1` for `|r| ≤ k`, else `k / |r|`.
+    Huber,
+    /// Tukey biweight: `w = (1 − (r/k)²)²` for `|r| ≤ k`, else `0`.
+    Tukey,
+}
+
+impl RobustKernel {
+    /// Robust weight for a residual magnitude `r` given the tuning constant `k`.
+    pub fn weight<S: IcpScalar>(&self, r: S, k: S) -> S {
+        let r = r.abs();
+        match self {
+            RobustKernel::Huber => {
+                if r <= k {
+                    S::one()
+                } else {
+                    k / r
+                }
+            }
+            RobustKernel::Tukey => {
+                if r <= k {
+                    let u = S::one() - (r / k) * (r / k);
+                    u * u
+                } else {
+                    S::zero()
+                }
+            }
+        }
+    }
+}
+
+/// Residual statistics gathered for a single reweighted Gauss-Newton iteration.
+///
+/// Callers can watch `rms` across iterations to detect divergence.
+#[derive(Clone, Copy, Debug)]
+pub struct IterationStats<S: IcpScalar> {
+    /// Root-mean-square correspondence residual before the update.
+    pub rms: S,
+    /// Mean absolute correspondence residual before the update.
+    pub mean_abs: S,
+    /// Largest absolute correspondence residual before the update.
+    pub max_abs: S,
+    /// Number of correspondences whose residual fell within the kernel band `k`.
+    pub inliers: usize,
+}
+
+/// One reweighted Gauss-Newton step of point-to-point ICP.
+///
+/// Each correspondence `p_i → q_i` contributes the two scalar residual rows of
+/// `r_i = (p_i − q_i) + θ·p_i^⊥ + t` (with `p_i^⊥ = (−p_i.y, p_i.x)`), whose
+/// 1×3 Jacobians over `[θ, tx, ty]` are `[−p_i.y, 1, 0]` and `[p_i.x, 0, 1]`.
+/// Rows are weighted by `kernel` on the residual magnitude, and the 3×3 normal
+/// equations are solved for the incremental `[θ, tx, ty]`.
+pub(crate) fn gauss_newton_step<S: IcpScalar, TRef: ICPPoint<S>, TOther: ICPPoint<S>>(
+    scan1: &mut KDTreedIcpCollection<S, TRef>,
+    scan2: &mut ICPCollection<S, TOther>,
+    kernel: RobustKernel,
+    k: S,
+) -> (na::Vector2<S>, S, IterationStats<S>) {
+    let mut a = na::Matrix3::zeros();
+    let mut b = na::Vector3::zeros();
+
+    let mut sum_sq = S::zero();
+    let mut sum_abs = S::zero();
+    let mut max_abs = S::zero();
+    let mut inliers = 0;
+
+    for p in scan2.get_points().iter().map(|p| p.point()) {
+        let Some((q, _)) = scan1.closest_point_kd(p) else {
+            continue;
+        };
+        let e = p - q;
+        let r = crate::ops::sqrt(e.norm_squared());
+
+        sum_sq += r * r;
+        sum_abs += r;
+        max_abs = max_abs.max(r);
+        if r <= k {
+            inliers += 1;
+        }
+
+        let w = kernel.weight(r, k);
+        let row_x = na::Vector3::new(-p.y, S::one(), S::zero());
+        let row_y = na::Vector3::new(p.x, S::zero(), S::one());
+
+        a += (row_x * row_x.transpose() + row_y * row_y.transpose()) * w;
+        b -= (row_x * e.x + row_y * e.y) * w;
+    }
+
+    let x = match a.cholesky() {
+        Some(chol) => chol.solve(&b),
+        None => na::LU::new(a).solve(&b).unwrap_or_else(na::Vector3::zeros),
+    };
+
+    let n = na::convert::<f64, S>(scan2.get_points().len().max(1) as f64);
+    let stats = IterationStats {
+        rms: crate::ops::sqrt(sum_sq / n),
+        mean_abs: sum_abs / n,
+        max_abs,
+        inliers,
+    };
+
+    (na::Vector2::new(x.y, x.z), x.x, stats)
+}